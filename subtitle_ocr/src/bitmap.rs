@@ -1,8 +1,9 @@
 //! Black-and-white images in a format that's optimized for OCR calculations.
 
 use cast;
-use image::{Rgba, RgbaImage};
-use std::collections::HashMap;
+use image::{GrayImage, Luma, Rgba, RgbaImage};
+use rand::{thread_rng, Rng};
+use std::collections::{HashMap, HashSet};
 
 use errors::*;
 #[cfg(test)]
@@ -43,9 +44,452 @@ impl RgbaImageExt for RgbaImage {
     }
 }
 
+/// A read-only view onto a rectangular region of pixels.
+///
+/// Implemented by both `RgbaImage` itself and `RgbaSubImage`, so that
+/// `classify_colors` and friends can restrict their analysis to, say, a
+/// detected subtitle bounding box instead of an entire video frame.
+/// That matters because full-frame backgrounds would otherwise pollute
+/// the transparent/opaque ratios that drive `looks_like_shadow`.
+pub trait RgbaView {
+    /// The width of this view, in pixels.
+    fn width(&self) -> u32;
+    /// The height of this view, in pixels.
+    fn height(&self) -> u32;
+    /// Return the value of the pixel at `x` and `y` if those coordinates
+    /// fall inside the view, or `None` if they're out of bounds.
+    fn get_opt(&self, x: i32, y: i32) -> Option<&Rgba<u8>>;
+    /// Iterate over every `(x, y, pixel)` in this view, in row-major
+    /// order, with coordinates relative to the view.
+    fn enumerate_pixels<'a>(&'a self) -> Box<Iterator<Item = (u32, u32, &'a Rgba<u8>)> + 'a>;
+}
+
+impl RgbaView for RgbaImage {
+    fn width(&self) -> u32 {
+        RgbaImage::width(self)
+    }
+
+    fn height(&self) -> u32 {
+        RgbaImage::height(self)
+    }
+
+    fn get_opt(&self, x: i32, y: i32) -> Option<&Rgba<u8>> {
+        RgbaImageExt::get_opt(self, x, y)
+    }
+
+    fn enumerate_pixels<'a>(&'a self) -> Box<Iterator<Item = (u32, u32, &'a Rgba<u8>)> + 'a> {
+        Box::new(RgbaImage::enumerate_pixels(self))
+    }
+}
+
+/// A zero-copy view onto a rectangular sub-region of an `RgbaImage`.
+pub struct RgbaSubImage<'a> {
+    image: &'a RgbaImage,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl<'a> RgbaSubImage<'a> {
+    /// Create a view of `image` clipped to the rectangle starting at
+    /// `(x, y)` with the given `width` and `height`, intersected against
+    /// `image`'s own bounds (matching `RgbaImageExt::get_opt`'s bounds
+    /// behavior).
+    pub fn new(image: &'a RgbaImage, x: u32, y: u32, width: u32, height: u32)
+               -> RgbaSubImage<'a> {
+        let width = width.min(image.width().saturating_sub(x));
+        let height = height.min(image.height().saturating_sub(y));
+        RgbaSubImage { image: image, x: x, y: y, width: width, height: height }
+    }
+}
+
+impl<'a> RgbaView for RgbaSubImage<'a> {
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn get_opt(&self, x: i32, y: i32) -> Option<&Rgba<u8>> {
+        if x < 0 || y < 0 {
+            return None;
+        }
+        let x = cast::u32(x).expect("x should be in bounds");
+        let y = cast::u32(y).expect("y should be in bounds");
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        self.image.get_opt(cast::i32(self.x).expect("x offset should fit in i32") + cast::i32(x).expect("x should fit in i32"),
+                            cast::i32(self.y).expect("y offset should fit in i32") + cast::i32(y).expect("y should fit in i32"))
+    }
+
+    fn enumerate_pixels<'b>(&'b self) -> Box<Iterator<Item = (u32, u32, &'b Rgba<u8>)> + 'b> {
+        let (x0, y0, image) = (self.x, self.y, self.image);
+        Box::new((0..self.height).flat_map(move |y| {
+            (0..self.width).map(move |x| (x, y, image.get_pixel(x0 + x, y0 + y)))
+        }))
+    }
+}
+
+#[test]
+fn rgba_sub_image_clips_to_parent_bounds() {
+    // A 4x4 parent image; requesting a 10x10 view starting at (2, 2)
+    // should clip down to the 2x2 region that actually fits.
+    let image = RgbaImage::new(4, 4);
+    let view = RgbaSubImage::new(&image, 2, 2, 10, 10);
+    assert_eq!(view.width(), 2);
+    assert_eq!(view.height(), 2);
+
+    // A view starting entirely outside the parent clips to zero size
+    // rather than panicking or wrapping.
+    let view = RgbaSubImage::new(&image, 10, 10, 5, 5);
+    assert_eq!(view.width(), 0);
+    assert_eq!(view.height(), 0);
+}
+
+#[test]
+fn rgba_sub_image_get_opt_translates_coordinates() {
+    let mut image = RgbaImage::new(4, 4);
+    image.put_pixel(1, 1, rgba_hex(0x112233ff));
+    image.put_pixel(2, 1, rgba_hex(0x445566ff));
+
+    let view = RgbaSubImage::new(&image, 1, 1, 2, 2);
+    assert_eq!(view.get_opt(0, 0), Some(&rgba_hex(0x112233ff)));
+    assert_eq!(view.get_opt(1, 0), Some(&rgba_hex(0x445566ff)));
+
+    // Just outside the view on every side is None, even though some of
+    // those coordinates are still inside the parent image.
+    assert_eq!(view.get_opt(-1, 0), None);
+    assert_eq!(view.get_opt(0, -1), None);
+    assert_eq!(view.get_opt(2, 0), None);
+    assert_eq!(view.get_opt(0, 2), None);
+}
+
+#[test]
+fn rgba_sub_image_enumerate_pixels_yields_view_relative_coordinates() {
+    let mut image = RgbaImage::new(4, 4);
+    image.put_pixel(1, 1, rgba_hex(0x112233ff));
+    image.put_pixel(2, 1, rgba_hex(0x445566ff));
+    image.put_pixel(1, 2, rgba_hex(0x778899ff));
+    image.put_pixel(2, 2, rgba_hex(0xaabbccff));
+
+    let view = RgbaSubImage::new(&image, 1, 1, 2, 2);
+    let pixels: HashMap<(u32, u32), Rgba<u8>> = view.enumerate_pixels()
+        .map(|(x, y, px)| ((x, y), *px))
+        .collect();
+
+    assert_eq!(pixels.len(), 4);
+    for (&(x, y), px) in &pixels {
+        assert_eq!(Some(px), view.get_opt(cast::i32(x).unwrap(), cast::i32(y).unwrap()));
+    }
+}
+
+/// A color represented in the perceptually-uniform Oklab color space.
+///
+/// We use this to cluster perceptually indistinguishable colors (for
+/// example, the dozens of near-identical edge colors produced by
+/// anti-aliased subtitle glyphs) before classifying them, so that they
+/// don't each get treated as a separate color.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Oklab {
+    l: f64,
+    a: f64,
+    b: f64,
+}
+
+impl Oklab {
+    /// Convert an sRGB color to Oklab, ignoring alpha.
+    fn from_rgba(px: &Rgba<u8>) -> Oklab {
+        fn linearize(c: u8) -> f64 {
+            let c = cast::f64(c) / 255.0;
+            if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        }
+
+        let r = linearize(px.data[0]);
+        let g = linearize(px.data[1]);
+        let b = linearize(px.data[2]);
+
+        let l = 0.4122 * r + 0.5363 * g + 0.0514 * b;
+        let m = 0.2119 * r + 0.6807 * g + 0.1074 * b;
+        let s = 0.0883 * r + 0.2817 * g + 0.6300 * b;
+
+        let l_ = l.cbrt();
+        let m_ = m.cbrt();
+        let s_ = s.cbrt();
+
+        Oklab {
+            l: 0.2105 * l_ + 0.7936 * m_ - 0.0041 * s_,
+            a: 1.9780 * l_ - 2.4286 * m_ + 0.4506 * s_,
+            b: 0.0259 * l_ + 0.7828 * m_ - 0.8087 * s_,
+        }
+    }
+
+    /// Euclidean distance between two Oklab colors.
+    fn distance(&self, other: &Oklab) -> f64 {
+        let dl = self.l - other.l;
+        let da = self.a - other.a;
+        let db = self.b - other.b;
+        (dl * dl + da * da + db * db).sqrt()
+    }
+}
+
+/// Colors whose Oklab distance falls below this threshold are considered
+/// perceptually indistinguishable and merged into the same cluster.
+const COLOR_CLUSTER_THRESHOLD: f64 = 0.05;
+
+/// Merge perceptually indistinguishable opaque colors in `image` into
+/// representative clusters.
+///
+/// Anti-aliased subtitle glyphs produce dozens of near-identical edge
+/// colors, which would otherwise each be treated as a distinct color by
+/// `classify_colors`, inflating the adjacency statistics and breaking
+/// the shadow heuristics. We process colors from most to least frequent,
+/// greedily joining each one to the nearest existing cluster (in Oklab
+/// space) if it's close enough, and keep the most frequent member of
+/// each cluster as its canonical representative. Transparent colors are
+/// left out of this, since alpha-based transparency detection already
+/// handles them.
+fn cluster_opaque_colors<V: RgbaView>(image: &V) -> HashMap<Rgba<u8>, Rgba<u8>> {
+    let mut counts = HashMap::new();
+    for (_, _, px) in image.enumerate_pixels() {
+        if !px.is_transparent() {
+            *counts.entry(*px).or_insert(0u64) += 1;
+        }
+    }
+
+    let mut by_frequency: Vec<Rgba<u8>> = counts.keys().cloned().collect();
+    by_frequency.sort_by_key(|c| (::std::cmp::Reverse(counts[c]), c.data));
+
+    let mut clusters: Vec<(Rgba<u8>, Oklab)> = Vec::new();
+    let mut canonical = HashMap::new();
+    for c in by_frequency {
+        let lab = Oklab::from_rgba(&c);
+        let nearest = clusters.iter()
+            .map(|&(rep, rep_lab)| (rep, lab.distance(&rep_lab)))
+            .min_by(|a, b| a.1.partial_cmp(&b.1).expect("NaN in color distance"));
+        match nearest {
+            Some((rep, dist)) if dist < COLOR_CLUSTER_THRESHOLD => {
+                canonical.insert(c, rep);
+            }
+            _ => {
+                clusters.push((c, lab));
+                canonical.insert(c, c);
+            }
+        }
+    }
+    canonical
+}
+
+#[test]
+fn cluster_opaque_colors_merges_near_identical_colors_into_most_frequent() {
+    // 0x808080 and 0x818181 are close enough in Oklab space to merge;
+    // 0x000000 is far enough away to stay its own cluster. 0x808080 is
+    // the more frequent of the two near-identical colors, so it should
+    // be picked as the cluster's representative even though the rarer
+    // color is inserted into the image first.
+    let mut image = RgbaImage::new(4, 1);
+    image.put_pixel(0, 0, rgba_hex(0x818181ff));
+    image.put_pixel(1, 0, rgba_hex(0x808080ff));
+    image.put_pixel(2, 0, rgba_hex(0x808080ff));
+    image.put_pixel(3, 0, rgba_hex(0x000000ff));
+
+    let canonical = cluster_opaque_colors(&image);
+    assert_eq!(canonical.len(), 3);
+    assert_eq!(canonical[&rgba_hex(0x808080ff)], rgba_hex(0x808080ff));
+    assert_eq!(canonical[&rgba_hex(0x818181ff)], rgba_hex(0x808080ff));
+    assert_eq!(canonical[&rgba_hex(0x000000ff)], rgba_hex(0x000000ff));
+}
+
+/// The default number of palette entries produced by `quantize_palette`.
+const DEFAULT_PALETTE_SIZE: usize = 8;
+
+/// The maximum number of k-means iterations to run before giving up on
+/// convergence.
+const KMEANS_MAX_ITERATIONS: usize = 50;
+
+/// Stop iterating k-means once every centroid moves less than this
+/// distance (in Oklab space) during an update step.
+const KMEANS_EPSILON: f64 = 1e-4;
+
+/// The result of reducing an image's colors to a small dominant palette.
+pub struct QuantizedImage {
+    /// `image`, with every opaque pixel's color replaced by its nearest
+    /// palette color. Fully transparent pixels are left unchanged.
+    pub image: RgbaImage,
+    /// How many pixels were mapped to each palette color, keyed by that
+    /// color. Callers can use this to drop small, likely-noise clusters.
+    pub counts: HashMap<Rgba<u8>, u64>,
+}
+
+/// Find the index of the centroid nearest to `lab`.
+fn nearest_centroid(centroids: &[Oklab], lab: &Oklab) -> usize {
+    centroids.iter()
+        .enumerate()
+        .map(|(i, c)| (i, c.distance(lab)))
+        .min_by(|a, b| a.1.partial_cmp(&b.1).expect("NaN in color distance"))
+        .map(|(i, _)| i)
+        .expect("centroids should not be empty")
+}
+
+/// Choose a weighted-random sample from `samples`, where `samples` is a
+/// list of `(color, oklab color, weight)` triples.
+fn weighted_choice<R: Rng>(rng: &mut R, samples: &[(Rgba<u8>, Oklab, u64)]) -> Oklab {
+    let total: u64 = samples.iter().map(|&(_, _, w)| w).sum();
+    let mut pick = rng.gen_range(0, total.max(1));
+    for &(_, lab, weight) in samples {
+        if pick < weight {
+            return lab;
+        }
+        pick -= weight;
+    }
+    samples.last().expect("samples should not be empty").1
+}
+
+/// Pick `k` initial centroids from `samples` using k-means++: the first
+/// centroid is chosen at random (weighted by pixel count), and each
+/// subsequent centroid is chosen with probability proportional to its
+/// squared distance to the nearest centroid chosen so far.
+fn init_centroids_kmeans_plus_plus(samples: &[(Rgba<u8>, Oklab, u64)], k: usize) -> Vec<Oklab> {
+    let mut rng = thread_rng();
+    let mut centroids = vec![weighted_choice(&mut rng, samples)];
+
+    while centroids.len() < k && centroids.len() < samples.len() {
+        let weighted: Vec<(Rgba<u8>, Oklab, u64)> = samples.iter()
+            .map(|&(c, lab, weight)| {
+                let d = centroids[nearest_centroid(&centroids, &lab)].distance(&lab);
+                // Oklab distances are mostly well below 1, so scale the
+                // squared distance up before rounding it to an integer
+                // weight, or nearly every weight would truncate to 0.
+                (c, lab, cast::u64(cast::f64(weight) * d * d * 1e6).unwrap_or(0))
+            })
+            .collect();
+        if weighted.iter().all(|&(_, _, w)| w == 0) {
+            break;
+        }
+        centroids.push(weighted_choice(&mut rng, &weighted));
+    }
+
+    centroids
+}
+
+/// Reduce `image` to a dominant palette of (approximately) `k` colors
+/// using weighted k-means clustering in Oklab space.
+///
+/// JPEG-artifacted or gradient-filled subtitle stills can contain a huge
+/// number of almost-but-not-quite-identical colors. Quantizing down to a
+/// handful of centroids before `classify_colors` collapses those into a
+/// handful of real ink/shadow/background colors.
+pub fn quantize_palette(image: &RgbaImage, k: usize) -> QuantizedImage {
+    // Sample every opaque color, weighted by how often it occurs. Fully
+    // transparent pixels are never fed to the clustering.
+    let mut weights: HashMap<Rgba<u8>, u64> = HashMap::new();
+    for px in image.pixels() {
+        if !px.is_transparent() {
+            *weights.entry(*px).or_insert(0) += 1;
+        }
+    }
+    let samples: Vec<(Rgba<u8>, Oklab, u64)> = weights.iter()
+        .map(|(&c, &w)| (c, Oklab::from_rgba(&c), w))
+        .collect();
+    if samples.is_empty() {
+        return QuantizedImage { image: image.clone(), counts: HashMap::new() };
+    }
+
+    let mut centroids = init_centroids_kmeans_plus_plus(&samples, k);
+    for _ in 0..KMEANS_MAX_ITERATIONS {
+        // Assign step: bucket every sample under its nearest centroid.
+        let mut sums = vec![(0.0, 0.0, 0.0, 0u64); centroids.len()];
+        for &(_, lab, weight) in &samples {
+            let idx = nearest_centroid(&centroids, &lab);
+            let w = cast::f64(weight);
+            sums[idx].0 += lab.l * w;
+            sums[idx].1 += lab.a * w;
+            sums[idx].2 += lab.b * w;
+            sums[idx].3 += weight;
+        }
+
+        // Update step: move each centroid to its bucket's weighted mean.
+        let mut max_shift = 0.0_f64;
+        for (centroid, &(sum_l, sum_a, sum_b, total)) in centroids.iter_mut().zip(&sums) {
+            if total == 0 {
+                continue;
+            }
+            let total = cast::f64(total);
+            let updated = Oklab { l: sum_l / total, a: sum_a / total, b: sum_b / total };
+            max_shift = max_shift.max(centroid.distance(&updated));
+            *centroid = updated;
+        }
+        if max_shift < KMEANS_EPSILON {
+            break;
+        }
+    }
+
+    // We only have a forward sRGB -> Oklab conversion, so pick each
+    // centroid's nearest real sample color as its sRGB representative.
+    let mut representatives: Vec<Option<Rgba<u8>>> = vec![None; centroids.len()];
+    let mut representative_dist = vec![f64::INFINITY; centroids.len()];
+    for &(c, lab, _) in &samples {
+        let idx = nearest_centroid(&centroids, &lab);
+        let dist = centroids[idx].distance(&lab);
+        if dist < representative_dist[idx] {
+            representative_dist[idx] = dist;
+            representatives[idx] = Some(c);
+        }
+    }
+
+    // Map every opaque pixel to its nearest centroid's sRGB
+    // representative, and count how many pixels ended up there.
+    let mut counts = HashMap::new();
+    let mut out = image.clone();
+    for px in out.pixels_mut() {
+        if px.is_transparent() {
+            continue;
+        }
+        let idx = nearest_centroid(&centroids, &Oklab::from_rgba(px));
+        if let Some(rep) = representatives[idx] {
+            *px = rep;
+            *counts.entry(rep).or_insert(0u64) += 1;
+        }
+    }
+
+    QuantizedImage { image: out, counts }
+}
+
+/// Like `quantize_palette`, but using the default palette size.
+pub fn quantize_palette_default(image: &RgbaImage) -> QuantizedImage {
+    quantize_palette(image, DEFAULT_PALETTE_SIZE)
+}
+
+#[test]
+fn quantize_palette_collapses_noisy_colors_and_preserves_transparency() {
+    // A noisy dark cluster, a noisy light cluster, and a transparent
+    // pixel. Quantizing to k=2 should collapse the five opaque, mostly
+    // distinct colors down to at most 2 palette entries, while leaving
+    // the transparent pixel untouched.
+    let mut image = RgbaImage::new(6, 1);
+    image.put_pixel(0, 0, rgba_hex(0x000000ff));
+    image.put_pixel(1, 0, rgba_hex(0x040404ff));
+    image.put_pixel(2, 0, rgba_hex(0xf0f0f0ff));
+    image.put_pixel(3, 0, rgba_hex(0xf4f4f4ff));
+    image.put_pixel(4, 0, rgba_hex(0xffffffff));
+    image.put_pixel(5, 0, rgba_hex(0x00000000));
+
+    let quantized = quantize_palette(&image, 2);
+    assert!(quantized.counts.len() <= 2);
+    assert_eq!(quantized.counts.values().sum::<u64>(), 5);
+    assert_eq!(*quantized.image.get_pixel(5, 0), rgba_hex(0x00000000));
+}
+
 /// Different kinds of colors we might find in an image.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-enum ColorType {
+pub enum ColorType {
     /// This color is transparent.
     Transparent,
     /// This color appears to be a shadow color which we should treat as
@@ -104,14 +548,35 @@ impl AdjacentPixelInfo {
 }
 
 /// Classify the colors in an image as transparent or non-transparent.
-fn classify_colors(image: &RgbaImage) -> Result<HashMap<Rgba<u8>, ColorType>> {
+///
+/// `image` can be anything implementing `RgbaView`, not just a full
+/// `RgbaImage` -- for example, an `RgbaSubImage` restricted to a
+/// detected subtitle bounding box, so full-frame backgrounds don't
+/// pollute the statistics that drive `looks_like_shadow`.
+///
+/// Internally, colors are merged into clusters before classification,
+/// but the map we return is keyed by every *raw* pixel color we
+/// observed (not just each cluster's representative), so callers can
+/// look up any pixel's own color directly without redoing the
+/// clustering themselves.
+pub fn classify_colors<V: RgbaView>(image: &V) -> Result<HashMap<Rgba<u8>, ColorType>> {
+    // Merge perceptually indistinguishable opaque colors (e.g.
+    // anti-aliased glyph edges) into clusters before classifying, so
+    // that we run our adjacency/shadow logic on cluster representatives
+    // instead of every individual color.
+    let canonical = cluster_opaque_colors(image);
+    let cluster_of = |px: &Rgba<u8>| -> Rgba<u8> {
+        if px.is_transparent() { *px } else { canonical[px] }
+    };
+
     // First divide colors into transparent and opaque based on alpha.
     let mut classification = HashMap::new();
-    for px in image.pixels() {
+    for (_, _, px) in image.enumerate_pixels() {
+        let c = cluster_of(px);
         if px.is_transparent() {
-            classification.entry(*px).or_insert(ColorType::Transparent);
+            classification.entry(c).or_insert(ColorType::Transparent);
         } else {
-            classification.entry(*px).or_insert(ColorType::Opaque);
+            classification.entry(c).or_insert(ColorType::Opaque);
         }
     }
     debug!("color classification (initial): {:?}", &classification);
@@ -129,6 +594,8 @@ fn classify_colors(image: &RgbaImage) -> Result<HashMap<Rgba<u8>, ColorType>> {
         if px.is_transparent() {
             continue;
         }
+        let c = cluster_of(px);
+
         // Look at the 3x3 grid around this pixel.
         for &dy in &[-1, 0, 1] {
             for &dx in &[-1, 0, 1] {
@@ -141,8 +608,8 @@ fn classify_colors(image: &RgbaImage) -> Result<HashMap<Rgba<u8>, ColorType>> {
                 let px_adj_opt =
                     image.get_opt(cast::i32(x)? + dx, cast::i32(y)? + dy);
 
-                // Don't count pixels of the same color.
-                if px_adj_opt == Some(px) {
+                // Don't count pixels in the same cluster as us.
+                if px_adj_opt.map(&cluster_of) == Some(c) {
                     continue;
                 }
 
@@ -150,10 +617,10 @@ fn classify_colors(image: &RgbaImage) -> Result<HashMap<Rgba<u8>, ColorType>> {
                 let ct_adj = px_adj_opt
                     .map_or_else(|| ColorType::Transparent,
                                  |px_adj| {
-                                     *classification.get(px_adj)
+                                     *classification.get(&cluster_of(px_adj))
                                          .expect("unknown classification")
                                  });
-                adjacent.get_mut(px)
+                adjacent.get_mut(&c)
                     .expect("unknown adjacent color")
                     .incr_count(ct_adj);
             }
@@ -184,7 +651,125 @@ fn classify_colors(image: &RgbaImage) -> Result<HashMap<Rgba<u8>, ColorType>> {
     }
 
     debug!("color classification (final): {:?}", &classification);
-    Ok(classification)
+
+    // `classification` is currently keyed by each color's cluster
+    // representative. Expand it so every raw color we saw (including
+    // non-representative members of a cluster, like anti-aliased edge
+    // colors) maps directly to its cluster's final `ColorType`.
+    let mut by_raw_color = HashMap::new();
+    for (c, ct) in &classification {
+        if c.is_transparent() {
+            by_raw_color.insert(*c, *ct);
+        }
+    }
+    for (raw, rep) in &canonical {
+        let ct = *classification.get(rep).expect("unknown classification");
+        by_raw_color.insert(*raw, ct);
+    }
+
+    Ok(by_raw_color)
+}
+
+/// How closely a pixel's color must match a `ColorRule`'s reference
+/// color for that rule to consider it a match.
+#[derive(Clone, Copy, Debug)]
+pub enum ColorTolerance {
+    /// Match if every RGB channel is within this many units of the
+    /// reference color.
+    Channel(u8),
+    /// Match if the color's Oklab distance from the reference color is
+    /// at most this radius.
+    Oklab(f64),
+}
+
+impl ColorTolerance {
+    /// Does `candidate` fall within this tolerance of `reference`?
+    fn contains(&self, reference: &Rgba<u8>, candidate: &Rgba<u8>) -> bool {
+        match *self {
+            ColorTolerance::Channel(tolerance) => {
+                (0..3).all(|i| {
+                    let r = i32::from(reference.data[i]);
+                    let c = i32::from(candidate.data[i]);
+                    (r - c).abs() <= i32::from(tolerance)
+                })
+            }
+            ColorTolerance::Oklab(radius) => {
+                let reference = Oklab::from_rgba(reference);
+                let candidate = Oklab::from_rgba(candidate);
+                reference.distance(&candidate) <= radius
+            }
+        }
+    }
+}
+
+/// A target ink color, used by `binarize` to build a black-and-white
+/// mask suitable for OCR.
+#[derive(Clone, Copy, Debug)]
+pub struct ColorRule {
+    color: Rgba<u8>,
+    tolerance: ColorTolerance,
+    shadow_tolerance: ColorTolerance,
+}
+
+impl ColorRule {
+    /// Match pixels within `tolerance` of `color` as ink, and pixels
+    /// within `shadow_tolerance` (but outside `tolerance`) as a darker
+    /// shadow band around that ink color.
+    pub fn new(color: Rgba<u8>, tolerance: ColorTolerance,
+               shadow_tolerance: ColorTolerance)
+               -> ColorRule {
+        ColorRule { color: color, tolerance: tolerance, shadow_tolerance: shadow_tolerance }
+    }
+
+    fn is_ink(&self, px: &Rgba<u8>) -> bool {
+        self.tolerance.contains(&self.color, px)
+    }
+
+    fn is_shadow(&self, px: &Rgba<u8>) -> bool {
+        !self.is_ink(px) && self.shadow_tolerance.contains(&self.color, px)
+    }
+}
+
+/// Binarize `image` against one or more ink-color `rules`, producing a
+/// black-and-white mask suitable for OCR.
+///
+/// Pixels matching a rule's ink color, or falling in that rule's shadow
+/// band, become black; everything else--including transparent pixels and
+/// colors that don't match any rule--becomes white. This mirrors the
+/// common OCR preprocessing step of mapping a tolerant color range to a
+/// single constant before recognition, and lets callers who already know
+/// their subtitle color (e.g. white text with a black outline) get clean
+/// input without relying solely on the adjacency heuristic.
+pub fn binarize(image: &RgbaImage, rules: &[ColorRule]) -> GrayImage {
+    let mut out = GrayImage::new(image.width(), image.height());
+    for (x, y, px) in image.enumerate_pixels() {
+        let is_ink = !px.is_transparent() &&
+            rules.iter().any(|rule| rule.is_ink(px) || rule.is_shadow(px));
+        let value = if is_ink { 0 } else { 255 };
+        out.put_pixel(x, y, Luma { data: [value] });
+    }
+    out
+}
+
+#[test]
+fn binarize_matches_ink_and_shadow_colors_to_black() {
+    // A pure-white ink pixel, a mid-gray pixel within the rule's shadow
+    // band, a black pixel outside both bands, and a transparent pixel.
+    let mut image = RgbaImage::new(4, 1);
+    image.put_pixel(0, 0, rgba_hex(0xffffffff));
+    image.put_pixel(1, 0, rgba_hex(0xccccccff));
+    image.put_pixel(2, 0, rgba_hex(0x000000ff));
+    image.put_pixel(3, 0, rgba_hex(0x00000000));
+
+    let rules = [
+        ColorRule::new(rgba_hex(0xffffffff), ColorTolerance::Channel(0),
+                        ColorTolerance::Oklab(0.3)),
+    ];
+    let mask = binarize(&image, &rules);
+    assert_eq!(mask.get_pixel(0, 0).data[0], 0);   // ink -> black
+    assert_eq!(mask.get_pixel(1, 0).data[0], 0);   // shadow band -> black
+    assert_eq!(mask.get_pixel(2, 0).data[0], 255); // unrelated color -> white
+    assert_eq!(mask.get_pixel(3, 0).data[0], 255); // transparent -> white
 }
 
 #[test]
@@ -200,3 +785,206 @@ fn classify_colors_as_transparent_and_opaque() {
     assert_eq!(*colors.get(&rgba_hex(0x999999ff)).unwrap(), ColorType::Opaque);
     assert_eq!(*colors.get(&rgba_hex(0xf0f0f0ff)).unwrap(), ColorType::Opaque);
 }
+
+/// A simple union-find (disjoint-set) structure, used to merge
+/// provisional labels during connected-component labeling.
+///
+/// Label `0` is reserved to mean "no label" and is never allocated a
+/// real set.
+struct UnionFind {
+    parent: Vec<u32>,
+}
+
+impl UnionFind {
+    fn new() -> UnionFind {
+        UnionFind { parent: vec![0] }
+    }
+
+    /// Allocate a new, distinct label.
+    fn make_set(&mut self) -> u32 {
+        let label = cast::u32(self.parent.len()).expect("too many labels");
+        self.parent.push(label);
+        label
+    }
+
+    /// Find the representative label for `label`'s set, flattening the
+    /// path to it as we go.
+    fn find(&mut self, label: u32) -> u32 {
+        let idx = cast::usize(label).expect("label should fit in usize");
+        if self.parent[idx] != label {
+            let root = self.find(self.parent[idx]);
+            self.parent[idx] = root;
+        }
+        self.parent[idx]
+    }
+
+    /// Merge the sets containing `a` and `b`.
+    fn union(&mut self, a: u32, b: u32) {
+        let ra = self.find(a);
+        let rb = self.find(b);
+        if ra != rb {
+            let idx = cast::usize(rb).expect("label should fit in usize");
+            self.parent[idx] = ra;
+        }
+    }
+}
+
+/// Look up the provisional label at `(x, y)`, or `None` if those
+/// coordinates fall outside `(width, height)`.
+fn label_at(labels: &[u32], width: u32, height: u32, x: i32, y: i32) -> Option<u32> {
+    if x < 0 || y < 0 {
+        return None;
+    }
+    let x = cast::u32(x).expect("x should be in bounds");
+    let y = cast::u32(y).expect("y should be in bounds");
+    if x >= width || y >= height {
+        None
+    } else {
+        Some(labels[cast::usize(y * width + x).expect("index should fit in usize")])
+    }
+}
+
+/// A connected group of `Opaque` pixels, suitable for cropping and
+/// recognizing a single glyph or word.
+#[derive(Clone, Debug)]
+pub struct Component {
+    /// The smallest rectangle containing every pixel in this component,
+    /// as `(x, y, width, height)`.
+    pub bounding_box: (u32, u32, u32, u32),
+    /// Every `(x, y)` pixel belonging to this component.
+    pub pixels: HashSet<(u32, u32)>,
+}
+
+/// Segment `classification` into connected components of `Opaque`
+/// pixels, treating `Shadow` and `Transparent` pixels as background, and
+/// filter out components smaller than `min_area`.
+///
+/// `ColorType::Shadow` exists specifically "to facilitate letter
+/// separation" (see above), so this is where we put that to use: by
+/// excluding shadow pixels from the foreground mask, letters that touch
+/// only through their shared shadow fall apart into separate components.
+/// This lets downstream OCR crop and recognize individual glyphs or
+/// words instead of feeding a whole subtitle line at once.
+///
+/// We use the classic two-pass connected-component labeling algorithm:
+/// the first pass assigns provisional labels and records equivalences
+/// between them via union-find, and the second pass flattens every
+/// label to its union-find root.
+pub fn segment_glyphs(image: &RgbaImage,
+                       classification: &HashMap<Rgba<u8>, ColorType>,
+                       min_area: u32)
+                       -> Vec<Component> {
+    let width = image.width();
+    let height = image.height();
+    let mut labels = vec![0u32; cast::usize(width * height).expect("size should fit in usize")];
+    let mut uf = UnionFind::new();
+
+    // Pass one: assign provisional labels, recording equivalences
+    // whenever two differently-labeled neighbors touch.
+    for y in 0..height {
+        for x in 0..width {
+            let px = image.get_pixel(x, y);
+            let ct = *classification.get(px).unwrap_or(&ColorType::Transparent);
+            if ct != ColorType::Opaque {
+                continue;
+            }
+
+            // We only need to look at already-visited neighbors: above,
+            // above-left, above-right, and left.
+            let x_i32 = cast::i32(x).expect("x should fit in i32");
+            let y_i32 = cast::i32(y).expect("y should fit in i32");
+            let mut neighbor_labels = Vec::new();
+            for &(dx, dy) in &[(-1, -1), (0, -1), (1, -1), (-1, 0)] {
+                if let Some(label) = label_at(&labels, width, height, x_i32 + dx, y_i32 + dy) {
+                    if label != 0 {
+                        neighbor_labels.push(label);
+                    }
+                }
+            }
+
+            let idx = cast::usize(y * width + x).expect("index should fit in usize");
+            if neighbor_labels.is_empty() {
+                labels[idx] = uf.make_set();
+            } else {
+                let first = neighbor_labels[0];
+                labels[idx] = first;
+                for &other in &neighbor_labels[1..] {
+                    uf.union(first, other);
+                }
+            }
+        }
+    }
+
+    // Pass two: flatten labels to their union-find roots and collect
+    // each component's pixels and bounding box.
+    let mut components: HashMap<u32, Component> = HashMap::new();
+    for y in 0..height {
+        for x in 0..width {
+            let idx = cast::usize(y * width + x).expect("index should fit in usize");
+            if labels[idx] == 0 {
+                continue;
+            }
+            let root = uf.find(labels[idx]);
+            let component = components.entry(root).or_insert_with(|| Component {
+                bounding_box: (x, y, 1, 1),
+                pixels: HashSet::new(),
+            });
+            component.pixels.insert((x, y));
+            let (bx, by, bw, bh) = component.bounding_box;
+            let (min_x, min_y) = (bx.min(x), by.min(y));
+            let (max_x, max_y) = ((bx + bw - 1).max(x), (by + bh - 1).max(y));
+            component.bounding_box =
+                (min_x, min_y, max_x - min_x + 1, max_y - min_y + 1);
+        }
+    }
+
+    components.into_iter()
+        .map(|(_, component)| component)
+        .filter(|component| cast::u32(component.pixels.len()).unwrap_or(::std::u32::MAX) >= min_area)
+        .collect()
+}
+
+#[test]
+fn segment_glyphs_splits_letters_joined_only_by_shadow() {
+    // Two opaque pixels separated by a single shadow pixel: if shadow
+    // were treated as foreground, they'd form one connected component,
+    // but since it's treated as background, they must come back as two.
+    let ink = rgba_hex(0x999999ff);
+    let shadow = rgba_hex(0x000000ff);
+    let mut image = RgbaImage::new(3, 1);
+    image.put_pixel(0, 0, ink);
+    image.put_pixel(1, 0, shadow);
+    image.put_pixel(2, 0, ink);
+
+    let mut classification = HashMap::new();
+    classification.insert(ink, ColorType::Opaque);
+    classification.insert(shadow, ColorType::Shadow);
+
+    let components = segment_glyphs(&image, &classification, 1);
+    assert_eq!(components.len(), 2);
+    for component in &components {
+        assert_eq!(component.bounding_box.2, 1);
+        assert_eq!(component.bounding_box.3, 1);
+    }
+}
+
+#[test]
+fn segment_glyphs_uses_raw_pixel_colors_from_classify_colors() {
+    // Four opaque pixels in a row, three of one color and one a
+    // near-identical color that `classify_colors` merges into the same
+    // cluster. Every pixel should still resolve to `Opaque` and end up
+    // in a single, fully-connected component -- not just the pixels
+    // whose raw color happens to equal the cluster's representative.
+    let mut image = RgbaImage::new(4, 1);
+    image.put_pixel(0, 0, rgba_hex(0x808080ff));
+    image.put_pixel(1, 0, rgba_hex(0x808080ff));
+    image.put_pixel(2, 0, rgba_hex(0x808080ff));
+    image.put_pixel(3, 0, rgba_hex(0x818181ff));
+
+    let classification = classify_colors(&image).unwrap();
+    let components = segment_glyphs(&image, &classification, 1);
+
+    assert_eq!(components.len(), 1);
+    assert_eq!(components[0].pixels.len(), 4);
+    assert_eq!(components[0].bounding_box, (0, 0, 4, 1));
+}